@@ -0,0 +1,310 @@
+// A local server for testing the network stack without the internet. Every request it gets is
+// answered with the next Reply from its script, over plain tcp or over TLS with a certificate from
+// a TestCa. Once the script is down to its last reply that one is used for everything after
+use super::*;
+use std::net::TcpListener;
+
+// How the server answers one request
+#[derive(Debug, Clone)]
+pub(crate) struct Reply {
+    // Written one after the other with `pause` in between, so a body can trickle in
+    parts: Vec<Vec<u8>>,
+    pause: Duration,
+    // Hang up once everything is written
+    close: bool,
+    // Keep the connection open without writing anything more, for timeouts
+    stall: bool,
+}
+
+impl Reply {
+    // Exactly these bytes, whatever they are
+    pub(crate) fn raw(bytes: impl Into<Vec<u8>>) -> Self {
+        Reply::parts(vec![bytes.into()])
+    }
+
+    pub(crate) fn parts(parts: Vec<Vec<u8>>) -> Self {
+        Reply {
+            parts,
+            pause: Duration::ZERO,
+            close: false,
+            stall: false,
+        }
+    }
+
+    // A 200 html page framed by Content-Length
+    pub(crate) fn ok(body: &str) -> Self {
+        Reply::raw(format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ))
+    }
+
+    // A 200 html page sent with one chunk per entry in `chunks`, each its own write
+    pub(crate) fn chunked(chunks: &[&str]) -> Self {
+        let mut parts = vec![
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nTransfer-Encoding: chunked\r\n\r\n"
+                .to_vec(),
+        ];
+        for chunk in chunks {
+            parts.push(format!("{:x}\r\n{}\r\n", chunk.len(), chunk).into_bytes());
+        }
+        parts.push(b"0\r\n\r\n".to_vec());
+        Reply::parts(parts)
+    }
+
+    // Reads the request and hangs up without answering, like a server dropping an idle socket
+    pub(crate) fn dropped() -> Self {
+        Reply::parts(Vec::new()).then_close()
+    }
+
+    // Reads the request and never answers
+    pub(crate) fn silent() -> Self {
+        Reply::parts(Vec::new()).then_stall()
+    }
+
+    pub(crate) fn slow(self, pause: Duration) -> Self {
+        Reply { pause, ..self }
+    }
+
+    pub(crate) fn then_close(self) -> Self {
+        Reply {
+            close: true,
+            ..self
+        }
+    }
+
+    pub(crate) fn then_stall(self) -> Self {
+        Reply {
+            stall: true,
+            ..self
+        }
+    }
+}
+
+// A request as the server read it
+#[derive(Debug, Clone)]
+pub(crate) struct Received {
+    pub(crate) head: String,
+    pub(crate) body: Vec<u8>,
+    // Which connection it came in on, counting accepted connections from 0
+    pub(crate) connection: usize,
+}
+
+impl Received {
+    pub(crate) fn request_line(&self) -> &str {
+        self.head.lines().next().unwrap_or("")
+    }
+}
+
+pub(crate) struct TestServer {
+    port: u16,
+    tls: bool,
+    received: mpsc::Receiver<Received>,
+}
+
+impl TestServer {
+    pub(crate) fn http(script: Vec<Reply>) -> Self {
+        TestServer::start(None, script)
+    }
+
+    pub(crate) fn https(config: rustls::ServerConfig, script: Vec<Reply>) -> Self {
+        TestServer::start(Some(Arc::new(config)), script)
+    }
+
+    fn start(tls: Option<Arc<rustls::ServerConfig>>, script: Vec<Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let script = Arc::new(Mutex::new(VecDeque::from(script)));
+        let (sender, received) = mpsc::channel();
+
+        let secure = tls.is_some();
+        std::thread::spawn(move || {
+            for (connection, tcp) in listener.incoming().flatten().enumerate() {
+                let (tls, script, sender) = (tls.clone(), script.clone(), sender.clone());
+                std::thread::spawn(move || {
+                    let served = Served {
+                        connection,
+                        script,
+                        sender,
+                    };
+                    match tls {
+                        Some(config) => {
+                            let connection = rustls::ServerConnection::new(config).unwrap();
+                            served.serve(StreamOwned::new(connection, tcp));
+                        }
+                        None => served.serve(tcp),
+                    }
+                });
+            }
+        });
+
+        TestServer {
+            port,
+            tls: secure,
+            received,
+        }
+    }
+
+    // TLS servers go by localhost, since that's the name TestCa certificates are for
+    pub(crate) fn url(&self, path: &str) -> Url {
+        let address = match self.tls {
+            true => format!("https://localhost:{}{}", self.port, path),
+            false => format!("http://127.0.0.1:{}{}", self.port, path),
+        };
+        Url::parse(&address).unwrap()
+    }
+
+    // The next `count` requests, waiting a little for each so it doesn't race the server thread
+    pub(crate) fn received(&self, count: usize) -> Vec<Received> {
+        (0..count)
+            .map(|_| {
+                self.received
+                    .recv_timeout(Duration::from_secs(2))
+                    .expect("the test server didn't get the request")
+            })
+            .collect()
+    }
+}
+
+// One connection's worth of the server
+struct Served {
+    connection: usize,
+    script: Arc<Mutex<VecDeque<Reply>>>,
+    sender: mpsc::Sender<Received>,
+}
+
+impl Served {
+    fn serve(&self, stream: impl Hangup) {
+        let mut reader = BufReader::new(stream);
+        // Ends when the client goes away, or its handshake fails
+        while let Some((head, body)) = read_request(&mut reader) {
+            let reply = self.next_reply();
+            let _ = self.sender.send(Received {
+                head,
+                body,
+                connection: self.connection,
+            });
+
+            for (i, part) in reply.parts.iter().enumerate() {
+                if i > 0 {
+                    std::thread::sleep(reply.pause);
+                }
+                let stream = reader.get_mut();
+                if stream.write_all(part).and_then(|_| stream.flush()).is_err() {
+                    return;
+                }
+            }
+
+            if reply.stall {
+                std::thread::sleep(Duration::from_secs(5));
+                return;
+            }
+            if reply.close {
+                reader.get_mut().hang_up();
+                return;
+            }
+        }
+    }
+
+    fn next_reply(&self) -> Reply {
+        let mut script = self.script.lock().unwrap_or_else(PoisonError::into_inner);
+        match script.len() {
+            0 => Reply::dropped(),
+            1 => script[0].clone(),
+            _ => script.pop_front().unwrap(),
+        }
+    }
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<(String, Vec<u8>)> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        if line == "\r\n" {
+            break;
+        }
+        head.push_str(&line);
+    }
+
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some((head, body))
+}
+
+// Closing a TLS connection politely needs a close_notify first, tcp just goes away when dropped
+trait Hangup: Read + Write {
+    fn hang_up(&mut self) {}
+}
+
+impl Hangup for TcpStream {}
+
+impl Hangup for StreamOwned<rustls::ServerConnection, TcpStream> {
+    fn hang_up(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+    }
+}
+
+// A certificate authority made up for the test, along with certificates for localhost it signs
+pub(crate) struct TestCa {
+    pub(crate) issuer: rcgen::CertifiedIssuer<'static, rcgen::KeyPair>,
+}
+
+impl TestCa {
+    pub(crate) fn new() -> Self {
+        let mut params = rcgen::CertificateParams::new(Vec::<String>::new()).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "Test CA");
+        let issuer =
+            rcgen::CertifiedIssuer::self_signed(params, rcgen::KeyPair::generate().unwrap())
+                .unwrap();
+        TestCa { issuer }
+    }
+
+    pub(crate) fn certificate(&self) -> &rcgen::Certificate {
+        self.issuer.as_ref()
+    }
+
+    pub(crate) fn localhost_certificate(
+        &self,
+    ) -> (Vec<CertificateDer<'static>>, PrivateKeyDer<'static>) {
+        let key = rcgen::KeyPair::generate().unwrap();
+        let cert = rcgen::CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &self.issuer)
+            .unwrap();
+        let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+        (vec![cert.der().clone()], key)
+    }
+
+    // Serves localhost to anyone, the way most sites do
+    pub(crate) fn server_config(&self) -> rustls::ServerConfig {
+        let (chain, key) = self.localhost_certificate();
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(chain, key)
+            .unwrap()
+    }
+
+    // A session that trusts this CA and nothing else
+    pub(crate) fn session(&self) -> Session {
+        let mut roots = RootCertStore::empty();
+        roots.add(self.certificate().der().clone()).unwrap();
+        Session {
+            tls: TlsContext::with_roots(roots, &TlsSettings::default()),
+            ..Session::default()
+        }
+    }
+}